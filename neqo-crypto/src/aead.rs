@@ -0,0 +1,217 @@
+// Licensed under the Apache License, Version 2.0 <LICENSE-APACHE or
+// http://www.apache.org/licenses/LICENSE-2.0> or the MIT license
+// <LICENSE-MIT or http://opensource.org/licenses/MIT>, at your
+// option. This file may not be copied, modified, or distributed
+// except according to those terms.
+
+//! A small AEAD (seal/open) layer over the keys produced by [`crate::hkdf`],
+//! using the AES-GCM and ChaCha20-Poly1305 mechanisms NSS exposes through
+//! `PK11_Encrypt`/`PK11_Decrypt`.
+
+use std::{
+    mem::size_of,
+    os::raw::{c_uint, c_void},
+    sync::OnceLock,
+};
+
+use libc::{dlsym, RTLD_DEFAULT};
+
+use crate::{
+    constants::{Cipher, TLS_AES_128_GCM_SHA256, TLS_AES_256_GCM_SHA384, TLS_CHACHA20_POLY1305_SHA256},
+    err::{Error, Res},
+    p11::{
+        Item, PK11SymKey, PK11_GetKeyLength, SymKey, CKM_AES_GCM, CKM_NSS_CHACHA20_POLY1305,
+        CK_GCM_PARAMS, CK_MECHANISM_TYPE, CK_NSS_AEAD_PARAMS, SECStatus,
+    },
+};
+
+/// The length in bytes of the authentication tag this module always uses.
+const TAG_LEN: usize = 16;
+
+/// The length in bytes of the nonce both AES-GCM and ChaCha20-Poly1305
+/// expect here.
+pub(crate) const NONCE_LEN: usize = 12;
+
+type Pk11EncryptFn = unsafe extern "C" fn(
+    *mut PK11SymKey,
+    CK_MECHANISM_TYPE,
+    *mut Item,
+    *mut u8,
+    *mut c_uint,
+    c_uint,
+    *const u8,
+    c_uint,
+) -> SECStatus;
+type Pk11DecryptFn = Pk11EncryptFn;
+
+/// Resolve `PK11_Encrypt`/`PK11_Decrypt` via `dlsym` rather than linking
+/// against them directly, so that this module degrades gracefully (instead
+/// of failing to link) against older system NSS builds that lack the
+/// symbols. The result is cached after the first lookup.
+fn symbols() -> Option<(Pk11EncryptFn, Pk11DecryptFn)> {
+    static SYMBOLS: OnceLock<Option<(usize, usize)>> = OnceLock::new();
+    SYMBOLS
+        .get_or_init(|| unsafe {
+            let encrypt = dlsym(RTLD_DEFAULT, c"PK11_Encrypt".as_ptr());
+            let decrypt = dlsym(RTLD_DEFAULT, c"PK11_Decrypt".as_ptr());
+            if encrypt.is_null() || decrypt.is_null() {
+                None
+            } else {
+                Some((encrypt as usize, decrypt as usize))
+            }
+        })
+        .map(|(encrypt, decrypt)| unsafe {
+            (
+                std::mem::transmute::<*mut c_void, Pk11EncryptFn>(encrypt as *mut c_void),
+                std::mem::transmute::<*mut c_void, Pk11DecryptFn>(decrypt as *mut c_void),
+            )
+        })
+}
+
+/// Map a `Cipher` to the PKCS#11 mechanism used to drive it through
+/// `PK11_Encrypt`/`PK11_Decrypt`.
+const fn mechanism(cipher: Cipher) -> Res<CK_MECHANISM_TYPE> {
+    Ok(CK_MECHANISM_TYPE::from(match cipher {
+        TLS_AES_128_GCM_SHA256 | TLS_AES_256_GCM_SHA384 => CKM_AES_GCM,
+        TLS_CHACHA20_POLY1305_SHA256 => CKM_NSS_CHACHA20_POLY1305,
+        _ => return Err(Error::UnsupportedCipher),
+    }))
+}
+
+/// The AEAD key length `Nk` in bytes for `cipher`. This is deliberately
+/// separate from [`crate::hkdf::key_size`], which returns the suite's hash
+/// output length (32/48), not its AEAD key length: conflating the two
+/// previously caused a "128-bit" key to be derived and run as AES-256-GCM.
+pub(crate) const fn key_size(cipher: Cipher) -> Res<usize> {
+    Ok(match cipher {
+        TLS_AES_128_GCM_SHA256 => 16,
+        TLS_AES_256_GCM_SHA384 | TLS_CHACHA20_POLY1305_SHA256 => 32,
+        _ => return Err(Error::UnsupportedCipher),
+    })
+}
+
+/// The mechanism parameter block for a single seal/open call. NSS uses a
+/// different struct (and a different tag-length unit) for AES-GCM than it
+/// does for `CKM_NSS_CHACHA20_POLY1305`, so these can't share a shape.
+enum Params {
+    Gcm(CK_GCM_PARAMS),
+    ChaCha(CK_NSS_AEAD_PARAMS),
+}
+
+impl Params {
+    fn new(cipher: Cipher, nonce: &[u8], aad: &[u8]) -> Res<Self> {
+        Ok(match cipher {
+            TLS_AES_128_GCM_SHA256 | TLS_AES_256_GCM_SHA384 => Self::Gcm(CK_GCM_PARAMS {
+                pIv: nonce.as_ptr().cast_mut(),
+                ulIvLen: c_uint::try_from(nonce.len())?,
+                pAAD: aad.as_ptr().cast_mut(),
+                ulAADLen: c_uint::try_from(aad.len())?,
+                ulTagBits: c_uint::try_from(TAG_LEN * 8)?,
+            }),
+            TLS_CHACHA20_POLY1305_SHA256 => Self::ChaCha(CK_NSS_AEAD_PARAMS {
+                pNonce: nonce.as_ptr().cast_mut(),
+                ulNonceLen: c_uint::try_from(nonce.len())?,
+                pAAD: aad.as_ptr().cast_mut(),
+                ulAADLen: c_uint::try_from(aad.len())?,
+                ulTagLen: c_uint::try_from(TAG_LEN)?,
+            }),
+            _ => return Err(Error::UnsupportedCipher),
+        })
+    }
+
+    /// Wrap this parameter block in the `Item` that `PK11_Encrypt`/
+    /// `PK11_Decrypt` expect as their mechanism parameter.
+    fn as_item(&self) -> Res<Item> {
+        let (ptr, len) = match self {
+            Self::Gcm(p) => ((p as *const CK_GCM_PARAMS).cast::<u8>(), size_of::<CK_GCM_PARAMS>()),
+            Self::ChaCha(p) => (
+                (p as *const CK_NSS_AEAD_PARAMS).cast::<u8>(),
+                size_of::<CK_NSS_AEAD_PARAMS>(),
+            ),
+        };
+        Item::wrap(unsafe { std::slice::from_raw_parts(ptr, len) })
+    }
+}
+
+/// Encrypt `plaintext` with `key`, returning `ciphertext || tag`.
+///
+/// # Errors
+///
+/// Errors returned if the cipher is unsupported, `key` isn't sized for it,
+/// the system NSS build lacks `PK11_Encrypt`, or the NSS call fails.
+pub fn seal(cipher: Cipher, key: &SymKey, nonce: &[u8], aad: &[u8], plaintext: &[u8]) -> Res<Vec<u8>> {
+    let (encrypt, _) = symbols().ok_or(Error::InternalError)?;
+    check_key_size(cipher, key)?;
+    let mech = mechanism(cipher)?;
+    let params = Params::new(cipher, nonce, aad)?;
+    let max_len = plaintext.len() + TAG_LEN;
+    let mut out = vec![0u8; max_len];
+    let mut out_len: c_uint = 0;
+    unsafe {
+        encrypt(
+            **key,
+            mech,
+            &mut params.as_item()?,
+            out.as_mut_ptr(),
+            &mut out_len,
+            c_uint::try_from(max_len)?,
+            plaintext.as_ptr(),
+            c_uint::try_from(plaintext.len())?,
+        )
+    }?;
+    out.truncate(usize::try_from(out_len)?);
+    Ok(out)
+}
+
+/// Decrypt `ciphertext` (which includes the trailing tag) with `key`,
+/// returning the plaintext.
+///
+/// # Errors
+///
+/// Errors returned if the cipher is unsupported, `key` isn't sized for it,
+/// `ciphertext` is shorter than the tag, the system NSS build lacks
+/// `PK11_Decrypt`, or authentication fails.
+pub fn open(cipher: Cipher, key: &SymKey, nonce: &[u8], aad: &[u8], ciphertext: &[u8]) -> Res<Vec<u8>> {
+    let (_, decrypt) = symbols().ok_or(Error::InternalError)?;
+    check_key_size(cipher, key)?;
+    if ciphertext.len() < TAG_LEN {
+        return Err(Error::InvalidInput);
+    }
+    let mech = mechanism(cipher)?;
+    let params = Params::new(cipher, nonce, aad)?;
+    let mut out = vec![0u8; ciphertext.len()];
+    let mut out_len: c_uint = 0;
+    unsafe {
+        decrypt(
+            **key,
+            mech,
+            &mut params.as_item()?,
+            out.as_mut_ptr(),
+            &mut out_len,
+            c_uint::try_from(out.len())?,
+            ciphertext.as_ptr(),
+            c_uint::try_from(ciphertext.len())?,
+        )
+    }?;
+    out.truncate(usize::try_from(out_len)?);
+    Ok(out)
+}
+
+/// Whether this process has a system NSS build new enough to expose
+/// `PK11_Encrypt`/`PK11_Decrypt`.
+#[must_use]
+pub fn supported() -> bool {
+    symbols().is_some()
+}
+
+/// Validate that `key`'s length matches the AEAD key length `cipher`
+/// expects. This asks NSS for the key's length rather than extracting its
+/// value, since `key` may not even permit that.
+fn check_key_size(cipher: Cipher, key: &SymKey) -> Res<()> {
+    let expected = key_size(cipher)?;
+    let actual = usize::try_from(unsafe { PK11_GetKeyLength(**key) })?;
+    if actual != expected {
+        return Err(Error::UnsupportedCipher);
+    }
+    Ok(())
+}
@@ -16,8 +16,10 @@ use crate::{
     },
     err::{Error, Res},
     p11::{
-        Item, PK11Origin, PK11SymKey, PK11_ImportDataKey, Slot, SymKey, CKA_DERIVE,
-        CKM_HKDF_DERIVE, CK_ATTRIBUTE_TYPE, CK_MECHANISM_TYPE,
+        Item, PK11Context, PK11Origin, PK11SymKey, PK11_CreateContextBySymKey, PK11_DestroyContext,
+        PK11_DigestBegin, PK11_DigestFinal, PK11_DigestOp, PK11_ExtractKeyValue, PK11_GetKeyData,
+        PK11_ImportDataKey, Slot, SymKey, CKA_DERIVE, CKA_SIGN, CKM_HKDF_DERIVE, CKM_SHA256_HMAC,
+        CKM_SHA384_HMAC, CKM_SHA512_HMAC, CK_ATTRIBUTE_TYPE, CK_MECHANISM_TYPE,
     },
     random,
 };
@@ -40,14 +42,27 @@ experimental_api!(SSL_HkdfExpandLabel(
     secret: *mut *mut PK11SymKey,
 ));
 
-const MAX_KEY_SIZE: usize = 48;
-const fn key_size(version: Version, cipher: Cipher) -> Res<usize> {
+/// A pseudo-ciphersuite identifier (from the TLS private-use range,
+/// RFC 8446 B.4) selecting HKDF over SHA-512 rather than one of the actual
+/// TLS 1.3 suites. This lets callers that want a 64-byte PRK/OKM (e.g. for
+/// non-TLS protocols) drive this module the same way as the real suites.
+///
+/// [`extract`] and [`expand`] (and so [`extract_then_expand`]) support this
+/// cipher via a `CKM_SHA512_HMAC` path, since it isn't a real TLS 1.3 suite
+/// that NSS's `SSL_Hkdf*` functions know about. [`expand_label`] is TLS
+/// 1.3-specific (it wraps `info` in the `HkdfLabel` wire format those
+/// functions define) and does not support it.
+pub const HKDF_SHA512: Cipher = 0xff02;
+
+pub(crate) const MAX_KEY_SIZE: usize = 64;
+pub(crate) const fn key_size(version: Version, cipher: Cipher) -> Res<usize> {
     if version != TLS_VERSION_1_3 {
         return Err(Error::UnsupportedVersion);
     }
     let size = match cipher {
         TLS_AES_128_GCM_SHA256 | TLS_CHACHA20_POLY1305_SHA256 => 32,
         TLS_AES_256_GCM_SHA384 => 48,
+        HKDF_SHA512 => 64,
         _ => return Err(Error::UnsupportedCipher),
     };
     debug_assert!(size <= MAX_KEY_SIZE);
@@ -91,6 +106,29 @@ pub fn import_key(version: Version, buf: &[u8]) -> Res<SymKey> {
     SymKey::from_ptr(key_ptr)
 }
 
+/// Read back the raw bytes backing a [`SymKey`] derived by [`extract`] or
+/// [`expand_label`].
+///
+/// This is primarily useful for validating this module against published
+/// test vectors (which specify `IKM`/`salt`/`info` and the expected
+/// `PRK`/`OKM` as raw bytes) and for interop with other HKDF
+/// implementations. The internal slot already permits extracting the value
+/// of `CKA_DERIVE` keys imported via [`import_key`], so this doesn't change
+/// what's accessible, only how it's exposed.
+///
+/// # Errors
+///
+/// Errors returned if the NSS functions fail, for example because `key`
+/// does not permit its value to be extracted.
+pub fn key_data(key: &SymKey) -> Res<Vec<u8>> {
+    unsafe { PK11_ExtractKeyValue(**key) }?;
+    let item = unsafe { PK11_GetKeyData(**key) };
+    if item.is_null() {
+        return Err(Error::InternalError);
+    }
+    Ok(unsafe { (*item).as_slice()?.to_vec() })
+}
+
 /// Extract a PRK from the given salt and IKM using the algorithm defined in RFC 5869.
 ///
 /// # Errors
@@ -102,17 +140,40 @@ pub fn extract(
     salt: Option<&SymKey>,
     ikm: &SymKey,
 ) -> Res<SymKey> {
+    if cipher == HKDF_SHA512 {
+        return extract_sha512(version, salt, ikm);
+    }
     let mut prk: *mut PK11SymKey = null_mut();
     let salt_ptr: *mut PK11SymKey = salt.map_or(null_mut(), |s| **s);
     unsafe { SSL_HkdfExtract(version, cipher, salt_ptr, **ikm, &raw mut prk) }?;
     SymKey::from_ptr(prk)
 }
 
+/// `HKDF_SHA512` isn't a TLS 1.3 suite, so `SSL_HkdfExtract` can't compute
+/// its extract step; do it directly as `HMAC-SHA512(salt, IKM)`, per RFC
+/// 5869, falling back to a `HashLen`-sized all-zero salt when none is given.
+fn extract_sha512(version: Version, salt: Option<&SymKey>, ikm: &SymKey) -> Res<SymKey> {
+    let (_, hash_len) = hmac_mechanism(HKDF_SHA512)?;
+    let zero_salt;
+    let salt_key = match salt {
+        Some(s) => s,
+        None => {
+            zero_salt = import_key(version, &vec![0; hash_len])?;
+            &zero_salt
+        }
+    };
+    let ikm_bytes = key_data(ikm)?;
+    let prk_bytes = hmac(HKDF_SHA512, salt_key, &ikm_bytes)?;
+    import_key(version, &prk_bytes)
+}
+
 /// Expand a PRK using the HKDF-Expand-Label function defined in RFC 8446.
 ///
 /// # Errors
 ///
-/// Errors returned if inputs are too large or the NSS functions fail.
+/// Errors returned if inputs are too large, `cipher` is [`HKDF_SHA512`]
+/// (which has no TLS 1.3 `HkdfLabel` wire format to expand into — use
+/// [`expand`] instead), or the NSS functions fail.
 pub fn expand_label(
     version: Version,
     cipher: Cipher,
@@ -120,6 +181,9 @@ pub fn expand_label(
     handshake_hash: &[u8],
     label: &str,
 ) -> Res<SymKey> {
+    if cipher == HKDF_SHA512 {
+        return Err(Error::UnsupportedCipher);
+    }
     let l = label.as_bytes();
     let mut secret: *mut PK11SymKey = null_mut();
 
@@ -139,3 +203,223 @@ pub fn expand_label(
     }?;
     SymKey::from_ptr(secret)
 }
+
+/// The HMAC mechanism that underlies HKDF for the given cipher, and the
+/// length in bytes of the hash it produces (`HashLen` in RFC 5869 terms).
+const fn hmac_mechanism(cipher: Cipher) -> Res<(CK_MECHANISM_TYPE, usize)> {
+    Ok(match cipher {
+        TLS_AES_128_GCM_SHA256 | TLS_CHACHA20_POLY1305_SHA256 => {
+            (CK_MECHANISM_TYPE::from(CKM_SHA256_HMAC), 32)
+        }
+        TLS_AES_256_GCM_SHA384 => (CK_MECHANISM_TYPE::from(CKM_SHA384_HMAC), 48),
+        HKDF_SHA512 => (CK_MECHANISM_TYPE::from(CKM_SHA512_HMAC), 64),
+        _ => return Err(Error::UnsupportedCipher),
+    })
+}
+
+/// Compute `HMAC-Hash(key, data)` using the HMAC mechanism associated with
+/// `cipher`, without ever exposing `key` outside of NSS.
+fn hmac(cipher: Cipher, key: &SymKey, data: &[u8]) -> Res<Vec<u8>> {
+    let (mechanism, hash_len) = hmac_mechanism(cipher)?;
+    let ctx = unsafe { PK11_CreateContextBySymKey(mechanism, CKA_SIGN, **key, &Item::wrap(&[])?) };
+    if ctx.is_null() {
+        return Err(Error::InternalError);
+    }
+    let ctx = unsafe { &mut *(ctx as *mut PK11Context) };
+    let res = (|| -> Res<Vec<u8>> {
+        unsafe { PK11_DigestBegin(ctx) }?;
+        unsafe { PK11_DigestOp(ctx, data.as_ptr(), c_uint::try_from(data.len())?) }?;
+        let mut out = vec![0; hash_len];
+        let mut out_len: c_uint = 0;
+        unsafe {
+            PK11_DigestFinal(
+                ctx,
+                out.as_mut_ptr(),
+                &mut out_len,
+                c_uint::try_from(out.len())?,
+            )
+        }?;
+        out.truncate(usize::try_from(out_len)?);
+        Ok(out)
+    })();
+    unsafe { PK11_DestroyContext(ctx, true) };
+    res
+}
+
+/// Expand a PRK using the generic HKDF-Expand function defined in RFC 5869.
+///
+/// Unlike [`expand_label`], this does not wrap `info` in the TLS 1.3
+/// `HkdfLabel` structure and can produce output of any `length`, not just
+/// the suite's fixed key size.  This is useful for deriving IVs, nonces, or
+/// other secrets that don't correspond to a `SymKey`-sized output.
+///
+/// # Errors
+///
+/// Errors returned if `length` is larger than `255 * HashLen`, the cipher
+/// suite is unsupported, or the NSS functions fail.
+pub fn expand(
+    version: Version,
+    cipher: Cipher,
+    prk: &SymKey,
+    info: &[u8],
+    length: usize,
+) -> Res<Vec<u8>> {
+    if version != TLS_VERSION_1_3 {
+        return Err(Error::UnsupportedVersion);
+    }
+    let (_, hash_len) = hmac_mechanism(cipher)?;
+    let blocks = length.div_ceil(hash_len);
+    if blocks > 255 {
+        // `length` is caller-supplied and out of RFC 5869's range, not an
+        // internal invariant violation, so use a distinct error variant.
+        return Err(Error::InvalidInput);
+    }
+
+    let mut okm = Vec::with_capacity(blocks * hash_len);
+    let mut t = Vec::new();
+    for i in 1..=blocks {
+        let mut data = Vec::with_capacity(t.len() + info.len() + 1);
+        data.extend_from_slice(&t);
+        data.extend_from_slice(info);
+        data.push(u8::try_from(i)?);
+        t = hmac(cipher, prk, &data)?;
+        okm.extend_from_slice(&t);
+    }
+    okm.truncate(length);
+    Ok(okm)
+}
+
+/// Chain [`extract`] into [`expand`], deriving `length` bytes of output
+/// keying material directly from `salt` and `ikm`.
+///
+/// # Errors
+///
+/// See [`extract`] and [`expand`].
+pub fn extract_then_expand(
+    version: Version,
+    cipher: Cipher,
+    salt: Option<&SymKey>,
+    ikm: &SymKey,
+    info: &[u8],
+    length: usize,
+) -> Res<Vec<u8>> {
+    let prk = extract(version, cipher, salt, ikm)?;
+    expand(version, cipher, &prk, info, length)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    // RFC 5869, Appendix A.1: Test Case 1 (HMAC-SHA-256).
+    const IKM: [u8; 22] = [0x0b; 22];
+    const SALT: [u8; 13] = [
+        0x00, 0x01, 0x02, 0x03, 0x04, 0x05, 0x06, 0x07, 0x08, 0x09, 0x0a, 0x0b, 0x0c,
+    ];
+    const INFO: [u8; 10] = [0xf0, 0xf1, 0xf2, 0xf3, 0xf4, 0xf5, 0xf6, 0xf7, 0xf8, 0xf9];
+    const PRK: [u8; 32] = [
+        0x07, 0x77, 0x09, 0x36, 0x2c, 0x2e, 0x32, 0xdf, 0x0d, 0xdc, 0x3f, 0x0d, 0xc4, 0x7b, 0xba,
+        0x63, 0x90, 0xb6, 0xc7, 0x3b, 0xb5, 0x0f, 0x9c, 0x31, 0x22, 0xec, 0x84, 0x4a, 0xd7, 0xc2,
+        0xb3, 0xe5,
+    ];
+    const OKM: [u8; 42] = [
+        0x3c, 0xb2, 0x5f, 0x25, 0xfa, 0xac, 0xd5, 0x7a, 0x90, 0x43, 0x4f, 0x64, 0xd0, 0x36, 0x2f,
+        0x2a, 0x2d, 0x2d, 0x0a, 0x90, 0xcf, 0x1a, 0x5a, 0x4c, 0x5d, 0xb0, 0x2d, 0x56, 0xec, 0xc4,
+        0xc5, 0xbf, 0x34, 0x00, 0x72, 0x08, 0xd5, 0xb8, 0x87, 0x18, 0x58, 0x65,
+    ];
+
+    #[test]
+    fn rfc5869_test_case_1_extract() {
+        let salt = import_key(TLS_VERSION_1_3, &SALT).unwrap();
+        let ikm = import_key(TLS_VERSION_1_3, &IKM).unwrap();
+        let prk = extract(TLS_VERSION_1_3, TLS_AES_128_GCM_SHA256, Some(&salt), &ikm).unwrap();
+        assert_eq!(key_data(&prk).unwrap(), PRK);
+    }
+
+    #[test]
+    fn rfc5869_test_case_1_expand() {
+        let prk = import_key(TLS_VERSION_1_3, &PRK).unwrap();
+        let okm = expand(TLS_VERSION_1_3, TLS_AES_128_GCM_SHA256, &prk, &INFO, OKM.len()).unwrap();
+        assert_eq!(okm, OKM);
+    }
+
+    #[test]
+    fn rfc5869_test_case_1_extract_then_expand() {
+        let salt = import_key(TLS_VERSION_1_3, &SALT).unwrap();
+        let ikm = import_key(TLS_VERSION_1_3, &IKM).unwrap();
+        let okm = extract_then_expand(
+            TLS_VERSION_1_3,
+            TLS_AES_128_GCM_SHA256,
+            Some(&salt),
+            &ikm,
+            &INFO,
+            OKM.len(),
+        )
+        .unwrap();
+        assert_eq!(okm, OKM);
+    }
+
+    #[test]
+    fn expand_rejects_length_past_255_blocks() {
+        let prk = import_key(TLS_VERSION_1_3, &PRK).unwrap();
+        let length = 255 * 32 + 1;
+        assert!(matches!(
+            expand(TLS_VERSION_1_3, TLS_AES_128_GCM_SHA256, &prk, &INFO, length),
+            Err(Error::InvalidInput)
+        ));
+    }
+
+    // RFC 4231, Section 4.3: HMAC-SHA-512 Test Case 2. Exercises the
+    // `CKM_SHA512_HMAC` path that `HKDF_SHA512`'s `extract`/`expand` are
+    // built on (RFC 5869 itself only publishes SHA-256/SHA-1 HKDF vectors).
+    const HMAC_SHA512_KEY: &[u8] = b"Jefe";
+    const HMAC_SHA512_DATA: &[u8] = b"what do ya want for nothing?";
+    const HMAC_SHA512_TAG: [u8; 64] = [
+        0x16, 0x4b, 0x7a, 0x7b, 0xfc, 0xf8, 0x19, 0xe2, 0xe3, 0x95, 0xfb, 0xe7, 0x3b, 0x56, 0xe0,
+        0xa3, 0x87, 0xbd, 0x64, 0x22, 0x2e, 0x83, 0x1f, 0xd6, 0x10, 0x27, 0x0c, 0xd7, 0xea, 0x25,
+        0x05, 0x54, 0x97, 0x58, 0xbf, 0x75, 0xc0, 0x5a, 0x99, 0x4a, 0x6d, 0x03, 0x4f, 0x65, 0xf8,
+        0xf0, 0xe6, 0xfd, 0xca, 0xea, 0xb1, 0xa3, 0x4d, 0x4a, 0x6b, 0x4b, 0x63, 0x6e, 0x07, 0x0a,
+        0x38, 0xbc, 0xe7, 0x37,
+    ];
+
+    #[test]
+    fn hmac_sha512_known_answer() {
+        let key = import_key(TLS_VERSION_1_3, HMAC_SHA512_KEY).unwrap();
+        assert_eq!(
+            hmac(HKDF_SHA512, &key, HMAC_SHA512_DATA).unwrap(),
+            HMAC_SHA512_TAG
+        );
+    }
+
+    #[test]
+    fn generate_key_sha512_is_64_bytes() {
+        let key = generate_key(TLS_VERSION_1_3, HKDF_SHA512).unwrap();
+        assert_eq!(key_data(&key).unwrap().len(), 64);
+    }
+
+    #[test]
+    fn extract_then_expand_sha512_is_64_bytes_and_deterministic() {
+        let salt = import_key(TLS_VERSION_1_3, &SALT).unwrap();
+        let ikm = import_key(TLS_VERSION_1_3, &IKM).unwrap();
+        let okm_a =
+            extract_then_expand(TLS_VERSION_1_3, HKDF_SHA512, Some(&salt), &ikm, &INFO, 64)
+                .unwrap();
+        assert_eq!(okm_a.len(), 64);
+
+        let salt = import_key(TLS_VERSION_1_3, &SALT).unwrap();
+        let ikm = import_key(TLS_VERSION_1_3, &IKM).unwrap();
+        let okm_b =
+            extract_then_expand(TLS_VERSION_1_3, HKDF_SHA512, Some(&salt), &ikm, &INFO, 64)
+                .unwrap();
+        assert_eq!(okm_a, okm_b);
+    }
+
+    #[test]
+    fn expand_label_rejects_sha512() {
+        let prk = import_key(TLS_VERSION_1_3, &[0; 64]).unwrap();
+        assert!(matches!(
+            expand_label(TLS_VERSION_1_3, HKDF_SHA512, &prk, &[], "test"),
+            Err(Error::UnsupportedCipher)
+        ));
+    }
+}